@@ -16,6 +16,7 @@
 extern crate gfx;
 extern crate winit;
 extern crate cgmath;
+extern crate image;
 
 use gfx::{Adapter, CommandQueue, Device, FrameSync, GraphicsPoolExt, Surface, SwapChain};
 use gfx::traits::DeviceExt;
@@ -26,76 +27,182 @@ type DepthFormat = gfx::format::DepthStencil;
 gfx_defines!{
     vertex Vertex {
         pos: [f32; 3] = "a_Pos",
-        color: [f32; 3] = "a_Color",
+        normal: [f32; 3] = "a_Normal",
+    }
+
+    // Separate stream from `Vertex` so the compute prepass can overwrite just this buffer.
+    vertex ColorVertex {
+        color: [f32; 4] = "a_Color",
     }
 
     constant Locals {
-        transform: [[f32; 4]; 4] = "u_Transform",
+        model: [[f32; 4]; 4] = "u_Model",
+    }
+
+    constant Light {
+        position: [f32; 4] = "u_LightPosition",
+        color: [f32; 4] = "u_LightColor",
     }
 
     pipeline pipe {
         vbuf: gfx::VertexBuffer<Vertex> = (),
-        transform: gfx::Global<[[f32; 4]; 4]> = "u_Transform",
+        color_buf: gfx::VertexBuffer<ColorVertex> = (),
+        view: gfx::Global<[[f32; 4]; 4]> = "u_View",
+        eye_position: gfx::Global<[f32; 3]> = "u_EyePosition",
         locals: gfx::ConstantBuffer<Locals> = "Locals",
+        light: gfx::ConstantBuffer<Light> = "Light",
         out_color: gfx::RenderTarget<ColorFormat> = "Target0",
         out_depth: gfx::DepthTarget<DepthFormat> = gfx::preset::depth::LESS_EQUAL_WRITE,
     }
 }
 
-const CUBE_VERTS: [Vertex; 8] = [
-    // top
-    Vertex {
-        pos: [-1., -1., 1.],
-        color: [0., 1., 0.],
-    },
-    Vertex {
-        pos: [1., -1., 1.],
-        color: [0., 1., 0.],
-    },
-    Vertex {
-        pos: [1., 1., 1.],
-        color: [0., 1., 0.],
-    },
-    Vertex {
-        pos: [-1., 1., 1.],
+struct Face {
+    corners: [[f32; 3]; 4],
+    normal: [f32; 3],
+    color: [f32; 3],
+}
+
+const CUBE_FACES: [Face; 6] = [
+    Face {
+        // top
+        corners: [[-1., -1., 1.], [1., -1., 1.], [1., 1., 1.], [-1., 1., 1.]],
+        normal: [0., 0., 1.],
         color: [0., 1., 0.],
     },
-    // bottom
-    Vertex {
-        pos: [-1., -1., -1.],
+    Face {
+        // bottom
+        corners: [[-1., -1., -1.], [1., -1., -1.], [1., 1., -1.], [-1., 1., -1.]],
+        normal: [0., 0., -1.],
         color: [0., 0., 1.],
     },
-    Vertex {
-        pos: [1., -1., -1.],
-        color: [0., 0., 1.],
+    Face {
+        // +x
+        corners: [[1., -1., -1.], [1., 1., -1.], [1., 1., 1.], [1., -1., 1.]],
+        normal: [1., 0., 0.],
+        color: [1., 0., 0.],
     },
-    Vertex {
-        pos: [1., 1., -1.],
-        color: [0., 0., 1.],
+    Face {
+        // -x
+        corners: [[-1., -1., -1.], [-1., -1., 1.], [-1., 1., 1.], [-1., 1., -1.]],
+        normal: [-1., 0., 0.],
+        color: [1., 1., 0.],
     },
-    Vertex {
-        pos: [-1., 1., -1.],
-        color: [0., 0., 1.],
+    Face {
+        // +y
+        corners: [[-1., 1., -1.], [-1., 1., 1.], [1., 1., 1.], [1., 1., -1.]],
+        normal: [0., 1., 0.],
+        color: [0., 1., 1.],
+    },
+    Face {
+        // -y
+        corners: [[-1., -1., -1.], [1., -1., -1.], [1., -1., 1.], [-1., -1., 1.]],
+        normal: [0., -1., 0.],
+        color: [1., 0., 1.],
     },
 ];
 
-const CUBE_INDICES: [[u16; 6]; 6] = [
-    // top
-    [0, 1, 2, 2, 3, 0],
-    // bottom
-    [4, 5, 6, 6, 7, 4],
-    // right
-    [0, 1, 4, 4, 5, 1],
-    // left
-    [1, 2, 5, 5, 6, 2],
-    // bottom
-    [2, 3, 6, 6, 7, 3],
-    // right
-    [3, 0, 7, 7, 4, 0],
-];
+// Builds 24 vertices (4 per face, not 8 shared corners) so every face gets its own flat normal.
+fn build_cube() -> ([Vertex; 24], [[u16; 6]; 6]) {
+    let mut verts = [Vertex { pos: [0.; 3], normal: [0.; 3] }; 24];
+    let mut indices = [[0u16; 6]; 6];
+
+    for (face_index, face) in CUBE_FACES.iter().enumerate() {
+        let base = face_index as u16 * 4;
+        for (corner_index, corner) in face.corners.iter().enumerate() {
+            verts[face_index * 4 + corner_index] = Vertex { pos: *corner, normal: face.normal };
+        }
+        indices[face_index] = [base, base + 1, base + 2, base + 2, base + 3, base];
+    }
+
+    (verts, indices)
+}
+
+fn build_cube_colors() -> [ColorVertex; 24] {
+    let mut colors = [ColorVertex { color: [0.; 4] }; 24];
+
+    for (face_index, face) in CUBE_FACES.iter().enumerate() {
+        let color = [face.color[0], face.color[1], face.color[2], 1.0];
+        for corner_index in 0..4 {
+            colors[face_index * 4 + corner_index] = ColorVertex { color };
+        }
+    }
+
+    colors
+}
+
+fn create_color_vertex_buffer(
+    device: &mut backend::Device,
+) -> gfx::handle::Buffer<backend::Resources, ColorVertex> {
+    device
+        .create_buffer_immutable(&build_cube_colors(), gfx::buffer::Role::Vertex, gfx::memory::Bind::empty())
+        .expect("Can't create color buffer")
+}
 
 const CLEAR_COLOR: [f32; 4] = [0.1, 0.2, 0.3, 1.0];
 
+mod compute_prepass {
+    use gfx;
+    use gfx::traits::DeviceExt;
+    use gfx::Factory;
+    use super::backend;
+    use super::ColorVertex;
+
+    gfx_defines!{
+        pipeline compute_pipe {
+            time: gfx::Global<f32> = "u_Time",
+            colors: gfx::UnorderedAccess<ColorVertex> = "Colors",
+        }
+    }
+
+    pub struct ComputePrepass {
+        pso: backend::ComputePipeline,
+        data: compute_pipe::Data<backend::Resources>,
+    }
+
+    impl ComputePrepass {
+        pub fn new(device: &mut backend::Device, vertex_count: usize) -> Self {
+            let pso = device
+                .create_compute_pipeline_simple(
+                    include_bytes!(concat!(
+                        env!("CARGO_MANIFEST_DIR"),
+                        "/shader/animate_colors_430.glslc"
+                    )),
+                    "main",
+                    compute_pipe::new(),
+                )
+                .expect("Can't create compute pipeline");
+            let colors = device
+                .create_buffer(
+                    vertex_count,
+                    gfx::buffer::Role::Vertex,
+                    gfx::memory::Usage::Data,
+                    gfx::memory::Bind::UNORDERED_ACCESS,
+                )
+                .expect("Can't create compute color buffer");
+
+            ComputePrepass {
+                pso,
+                data: compute_pipe::Data { time: 0.0, colors },
+            }
+        }
+
+        // Barrier so the draw call sees the freshly written colors instead of racing the writes.
+        pub fn dispatch(&mut self, encoder: &mut backend::GraphicsEncoder, time: f32) {
+            self.data.time = time;
+            encoder.dispatch(&self.pso, &self.data, (1, 1, 1));
+            encoder.buffer_barrier(
+                self.data.colors.raw(),
+                gfx::memory::Access::SHADER_WRITE,
+                gfx::memory::Access::VERTEX_BUFFER_READ,
+            );
+        }
+
+        pub fn color_buffer(&self) -> gfx::handle::Buffer<backend::Resources, ColorVertex> {
+            self.data.colors.clone()
+        }
+    }
+}
+
 mod backend {
     extern crate gfx_device_gl;
     extern crate gfx_window_glutin;
@@ -108,15 +215,25 @@ mod backend {
     pub type WinAdapter = gfx_device_gl::Adapter;
     pub type Dimensions = (u32, u32);
 
+    pub type Resources = gfx_device_gl::Resources;
+    pub type Device = gfx_device_gl::Device;
+    pub type GraphicsQueue = gfx_device_gl::CommandQueue;
+    pub type SwapChain = gfx_window_glutin::SwapChain;
+    pub type GraphicsEncoder = gfx::Encoder<Resources, gfx_device_gl::CommandBuffer>;
+    pub type ComputePipeline = gfx::handle::ComputePipelineStateObject<Resources>;
+
     // TODO: Factor this out into struct
     pub fn get_surface_and_adapters(
         events_loop: &winit::EventsLoop,
+        samples: u8,
     ) -> (Dimensions, WinSurface, Vec<WinAdapter>) {
         // Create window
         let wb = glutin::WindowBuilder::new()
             .with_title("Triangle example".to_string())
             .with_dimensions(1024, 768);
-        let gl_builder = glutin::ContextBuilder::new().with_vsync(true);
+        let gl_builder = glutin::ContextBuilder::new().with_vsync(true).with_multisampling(
+            samples as u16,
+        );
         let window = glutin::GlWindow::new(wb, gl_builder, events_loop).expect("Can't get window");
         let dim = window.get_inner_size_points().expect(
             "Can't get window dimensions",
@@ -127,62 +244,264 @@ mod backend {
 
         (dim, out.0, out.1)
     }
+
+    pub fn get_headless_device_and_adapters(dim: Dimensions) -> (Device, Vec<WinAdapter>) {
+        let headless_context = glutin::HeadlessRendererBuilder::new(dim.0, dim.1)
+            .build()
+            .expect("Can't create headless GL context");
+        unsafe {
+            headless_context.make_current().expect(
+                "Can't make headless context current",
+            )
+        };
+
+        gfx_device_gl::create(
+            |s| headless_context.get_proc_address(s) as *const _,
+        )
+    }
 }
 
-pub fn main() {
-    use self::backend::get_surface_and_adapters;
+mod camera {
+    use cgmath::{Angle, Deg, InnerSpace, Matrix4, Point3, Rad, Vector3, perspective};
+    use winit;
 
-    fn mk_transform(dim: (u32, u32), angle: f32) -> [[f32; 4]; 4] {
-        use cgmath::{Deg, Matrix3, Matrix4, Point3, Quaternion, Rotation3, Vector3, perspective};
+    pub struct Camera {
+        pub target: Point3<f32>,
+        pub up: Vector3<f32>,
+        pub fov: Deg<f32>,
+        pub znear: f32,
+        pub zfar: f32,
+    }
 
-        let rot: Matrix3<f32> = Quaternion::from_angle_z(Deg(angle)).into();
+    impl Camera {
+        pub fn new(target: Point3<f32>) -> Self {
+            Camera {
+                target,
+                up: Vector3::unit_z(),
+                fov: Deg(45.),
+                znear: 1.0,
+                zfar: 100.0,
+            }
+        }
 
-        let default_view = Matrix4::look_at(
-            Point3::new(0f32, 0., 0.) + rot * Vector3::new(1.5f32, -5.0, 3.0),
-            Point3::new(0f32, 0.0, 0.0),
-            Vector3::unit_z(),
-        );
+        pub fn build_view_projection(
+            &self,
+            eye: Point3<f32>,
+            dim: (u32, u32),
+        ) -> [[f32; 4]; 4] {
+            let view = Matrix4::look_at(eye, self.target, self.up);
+            let proj = perspective(self.fov, dim.0 as f32 / dim.1 as f32, self.znear, self.zfar);
 
-        let proj = perspective(Deg(45.), dim.0 as f32 / dim.1 as f32, 1.0, 10.0);
+            (proj * view).into()
+        }
+    }
 
-        (proj * default_view).into()
+    pub struct CameraController {
+        azimuth: Rad<f32>,
+        elevation: Rad<f32>,
+        distance: f32,
+        dragging: bool,
+        last_cursor: Option<(f64, f64)>,
+        orbit_speed: f32,
+        dolly_speed: f32,
+        pan_speed: f32,
+        forward_pressed: bool,
+        backward_pressed: bool,
+        left_pressed: bool,
+        right_pressed: bool,
     }
 
-    // Create window
-    let mut events_loop = winit::EventsLoop::new();
-    let (mut dim, mut surface, adapters) = get_surface_and_adapters(&events_loop);
+    impl CameraController {
+        pub fn new(distance: f32) -> Self {
+            CameraController {
+                azimuth: Rad(0.0),
+                elevation: Rad(0.3),
+                distance,
+                dragging: false,
+                last_cursor: None,
+                orbit_speed: 0.01,
+                dolly_speed: 0.5,
+                pan_speed: 0.05,
+                forward_pressed: false,
+                backward_pressed: false,
+                left_pressed: false,
+                right_pressed: false,
+            }
+        }
 
-    // Open gpu (device and queues)
-    let gfx::Gpu {
-        mut device,
-        mut graphics_queues,
-        ..
-    } = adapters.get(0).expect("No adapters found").open_with(
-        |family, ty| {
-            (
-                (ty.supports_graphics() && surface.supports_queue(family)) as u32,
-                gfx::QueueType::Graphics,
-            )
-        },
-    );
-    let mut graphics_queue = graphics_queues.pop().expect(
-        "Unable to find a graphics queue.",
-    );
+        pub fn process_event(&mut self, event: &winit::WindowEvent) {
+            use winit::{ElementState, MouseButton, MouseScrollDelta, VirtualKeyCode};
+
+            match *event {
+                winit::WindowEvent::MouseInput { state, button: MouseButton::Left, .. } => {
+                    self.dragging = state == ElementState::Pressed;
+                    if !self.dragging {
+                        self.last_cursor = None;
+                    }
+                }
+                winit::WindowEvent::CursorMoved { position, .. } => {
+                    if self.dragging {
+                        if let Some((last_x, last_y)) = self.last_cursor {
+                            let dx = (position.0 - last_x) as f32;
+                            let dy = (position.1 - last_y) as f32;
+                            self.azimuth -= Rad(dx * self.orbit_speed);
+                            self.elevation = Rad((self.elevation.0 + dy * self.orbit_speed)
+                                .max(-1.5)
+                                .min(1.5));
+                        }
+                        self.last_cursor = Some(position);
+                    }
+                }
+                winit::WindowEvent::MouseWheel { delta, .. } => {
+                    let scroll = match delta {
+                        MouseScrollDelta::LineDelta(_, y) => y,
+                        MouseScrollDelta::PixelDelta(_, y) => y / 32.0,
+                    };
+                    self.distance = (self.distance - scroll * self.dolly_speed).max(1.0);
+                }
+                winit::WindowEvent::KeyboardInput { input, .. } => {
+                    let pressed = input.state == ElementState::Pressed;
+                    match input.virtual_keycode {
+                        Some(VirtualKeyCode::W) => self.forward_pressed = pressed,
+                        Some(VirtualKeyCode::S) => self.backward_pressed = pressed,
+                        Some(VirtualKeyCode::A) => self.left_pressed = pressed,
+                        Some(VirtualKeyCode::D) => self.right_pressed = pressed,
+                        _ => (),
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        pub fn update(&self, camera: &mut Camera) -> Point3<f32> {
+            let forward = Vector3::new(-self.azimuth.cos(), -self.azimuth.sin(), 0.0).normalize();
+            let right = Vector3::new(-self.azimuth.sin(), self.azimuth.cos(), 0.0).normalize();
+
+            if self.forward_pressed {
+                camera.target += forward * self.pan_speed;
+            }
+            if self.backward_pressed {
+                camera.target -= forward * self.pan_speed;
+            }
+            if self.left_pressed {
+                camera.target -= right * self.pan_speed;
+            }
+            if self.right_pressed {
+                camera.target += right * self.pan_speed;
+            }
+
+            let horizontal = self.distance * self.elevation.cos();
+            let offset = Vector3::new(
+                horizontal * self.azimuth.cos(),
+                horizontal * self.azimuth.sin(),
+                self.distance * self.elevation.sin(),
+            );
+
+            camera.target + offset
+        }
+    }
+}
+
+type ViewPair = (gfx::handle::RenderTargetView<backend::Resources, ColorFormat>,
+                 gfx::handle::DepthStencilView<backend::Resources, DepthFormat>);
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum GpuPreference {
+    HighPerformance,
+    LowPower,
+}
+
+// Low-power (integrated) is the default unless `--high-performance-gpu` is passed.
+fn parse_gpu_preference() -> GpuPreference {
+    let mut args = std::env::args();
+    if args.any(|arg| arg == "--high-performance-gpu") {
+        GpuPreference::HighPerformance
+    } else {
+        GpuPreference::LowPower
+    }
+}
+
+fn select_adapter(
+    adapters: &[backend::WinAdapter],
+    preference: GpuPreference,
+) -> &backend::WinAdapter {
+    use gfx::adapter::DeviceType;
+
+    // Explicit preference order, most to least preferred. Anything not listed here (e.g. a
+    // CPU/software adapter) ranks below every listed GPU type in both modes, so it's never
+    // picked over an integrated GPU just because it happened to come first.
+    let order: &[DeviceType] = match preference {
+        GpuPreference::HighPerformance => {
+            &[DeviceType::DiscreteGpu, DeviceType::IntegratedGpu, DeviceType::VirtualGpu]
+        }
+        GpuPreference::LowPower => {
+            &[DeviceType::IntegratedGpu, DeviceType::DiscreteGpu, DeviceType::VirtualGpu]
+        }
+    };
+
+    adapters
+        .iter()
+        .min_by_key(|adapter| {
+            order
+                .iter()
+                .position(|ty| *ty == adapter.info().device_type)
+                .unwrap_or(order.len())
+        })
+        .expect("No adapters found")
+}
+
+const DEFAULT_SAMPLE_COUNT: u8 = 4;
+
+// Reads `--samples=N` off the command line (1, 2, 4 or 8), falling back to DEFAULT_SAMPLE_COUNT.
+fn parse_sample_count() -> u8 {
+    std::env::args()
+        .find_map(|arg| if arg.starts_with("--samples=") {
+            arg["--samples=".len()..].parse().ok()
+        } else {
+            None
+        })
+        .filter(|samples| matches!(samples, 1 | 2 | 4 | 8))
+        .unwrap_or(DEFAULT_SAMPLE_COUNT)
+}
+
+fn create_pso(device: &mut backend::Device) -> gfx::PipelineState<backend::Resources, pipe::Meta> {
+    device
+        .create_pipeline_simple(
+            include_bytes!(concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/shader/triangle_150.glslv"
+            )),
+            include_bytes!(concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/shader/triangle_150.glslf"
+            )),
+            pipe::new(),
+        )
+        .unwrap()
+}
+
+fn build_swapchain_and_views(
+    device: &mut backend::Device,
+    surface: &mut backend::WinSurface,
+    graphics_queue: &backend::GraphicsQueue,
+    old_swap_chain: Option<backend::SwapChain>,
+) -> (backend::SwapChain, Vec<ViewPair>) {
+    use gfx::texture::{DepthStencilDesc, DepthStencilFlags, RenderDesc};
+    use gfx::handle::{DepthStencilView, RenderTargetView};
+    use gfx::memory::Typed;
+    use gfx::format::Formatted;
+
+    // Drop the old swapchain before asking the surface for a new one.
+    drop(old_swap_chain);
 
-    // Create swapchain
     let config = gfx::SwapchainConfig::new()
         .with_color::<ColorFormat>()
         .with_depth_stencil::<DepthFormat>();
-    let mut swap_chain = surface.build_swapchain(config, &graphics_queue);
+    let mut swap_chain = surface.build_swapchain(config, graphics_queue);
     let views = swap_chain
         .get_backbuffers()
         .into_iter()
         .map(|&(ref color, ref ds)| {
-            use gfx::texture::{DepthStencilDesc, DepthStencilFlags, RenderDesc};
-            use gfx::handle::{DepthStencilView, RenderTargetView};
-            use gfx::memory::Typed;
-            use gfx::format::Formatted;
-
             let color_desc = RenderDesc {
                 channel: ColorFormat::get_format().1,
                 level: 0,
@@ -207,39 +526,118 @@ pub fn main() {
         })
         .collect::<Vec<_>>();
 
-    let pso = device
-        .create_pipeline_simple(
-            include_bytes!(concat!(
-                env!("CARGO_MANIFEST_DIR"),
-                "/shader/triangle_150.glslv"
-            )),
-            include_bytes!(concat!(
-                env!("CARGO_MANIFEST_DIR"),
-                "/shader/triangle_150.glslf"
-            )),
-            pipe::new(),
-        )
-        .unwrap();
+    (swap_chain, views)
+}
+
+pub fn main() {
+    if std::env::args().any(|arg| arg == "--headless") {
+        let width = parse_headless_dimension("--width", 1024);
+        let height = parse_headless_dimension("--height", 768);
+        let output_path = std::env::args()
+            .find_map(|arg| if arg.starts_with("--output=") {
+                Some(arg["--output=".len()..].to_string())
+            } else {
+                None
+            })
+            .unwrap_or_else(|| "output.png".to_string());
+
+        run_headless(width, height, &output_path);
+    } else {
+        run_windowed();
+    }
+}
+
+// Reads `--width=N` / `--height=N` for the headless render target size.
+fn parse_headless_dimension(flag: &str, default: u32) -> u32 {
+    let prefix = format!("{}=", flag);
+    std::env::args()
+        .find_map(|arg| if arg.starts_with(&prefix) {
+            arg[prefix.len()..].parse().ok()
+        } else {
+            None
+        })
+        .unwrap_or(default)
+}
+
+fn run_windowed() {
+    use self::backend::get_surface_and_adapters;
+    use self::camera::{Camera, CameraController};
+    use cgmath::{Matrix4, Point3, SquareMatrix};
+
+    // Create window
+    let sample_count = parse_sample_count();
+    let mut events_loop = winit::EventsLoop::new();
+    let (mut dim, mut surface, adapters) = get_surface_and_adapters(&events_loop, sample_count);
+
+    // Open gpu (device and queues)
+    let mut compute_capable = false;
+    let gfx::Gpu {
+        mut device,
+        mut graphics_queues,
+        ..
+    } = select_adapter(&adapters, parse_gpu_preference()).open_with(
+        |family, ty| {
+            let wants_graphics = ty.supports_graphics() && surface.supports_queue(family);
+            if wants_graphics {
+                compute_capable = ty.supports_compute();
+            }
+            (wants_graphics as u32, gfx::QueueType::Graphics)
+        },
+    );
+    let mut graphics_queue = graphics_queues.pop().expect(
+        "Unable to find a graphics queue.",
+    );
+
+    // Gated on the queue actually opened for graphics, not just any compute-capable family.
+    let compute_requested = std::env::args().any(|arg| arg == "--compute");
+    let mut prepass = if compute_requested && compute_capable {
+        Some(compute_prepass::ComputePrepass::new(&mut device, 24))
+    } else {
+        None
+    };
+
+    // Create swapchain. The window's GL context already owns the multisampling requested above,
+    // so the backbuffer views themselves are multisampled; the driver resolves them on present.
+    let (mut swap_chain, mut views) =
+        build_swapchain_and_views(&mut device, &mut surface, &graphics_queue, None);
+
+    let pso = create_pso(&mut device);
 
-    let flat_indices: [u16; 6 * 6] = unsafe { std::mem::transmute(CUBE_INDICES) };
+    let (cube_verts, cube_indices) = build_cube();
+    let flat_indices: [u16; 6 * 6] = unsafe { std::mem::transmute(cube_indices) };
     let (vertex_buffer, slice) =
-        device.create_vertex_buffer_with_slice(&CUBE_VERTS, flat_indices.as_ref());
+        device.create_vertex_buffer_with_slice(&cube_verts, flat_indices.as_ref());
     let mut graphics_pool = graphics_queue.create_graphics_pool(1);
     let frame_semaphore = device.create_semaphore();
     let draw_semaphore = device.create_semaphore();
     let frame_fence = device.create_fence(false);
 
-    let mut angle = 45.;
+    let mut camera = Camera::new(Point3::new(0f32, 0., 0.));
+    let mut camera_controller = CameraController::new(6.0);
+    let eye = camera_controller.update(&mut camera);
+
+    let light = device.create_constant_buffer(1);
+
+    let color_buf = match prepass {
+        Some(ref prepass) => prepass.color_buffer(),
+        None => create_color_vertex_buffer(&mut device),
+    };
+
     let mut data = pipe::Data {
         vbuf: vertex_buffer,
-        transform: mk_transform(dim, angle),
+        color_buf,
+        view: camera.build_view_projection(eye, dim),
+        eye_position: eye.into(),
         locals: device.create_constant_buffer(1),
+        light,
         out_color: views[0].0.clone(),
         out_depth: views[0].1.clone(),
     };
 
     // main loop
     let mut running = true;
+    let mut resized = false;
+    let mut elapsed_seconds = 0.0f32;
     while running {
         // fetch events
         events_loop.poll_events(|event| if let winit::Event::WindowEvent {
@@ -255,15 +653,33 @@ pub fn main() {
                     ..
                 } => return,
                 winit::WindowEvent::Resized(width, height) => {
-                    dim = (width, height);
+                    // A minimized window fires Resized(0, 0); keep the last known size instead
+                    // of rebuilding the swapchain against it.
+                    if width > 0 && height > 0 {
+                        dim = (width, height);
+                        resized = true;
+                    }
                 }
-                _ => (),
+                other => camera_controller.process_event(&other),
             }
         });
 
-        angle += 1.;
-        angle %= 360.;
-        data.transform = mk_transform(dim, angle);
+        if resized {
+            let (new_swap_chain, new_views) = build_swapchain_and_views(
+                &mut device,
+                &mut surface,
+                &graphics_queue,
+                Some(swap_chain),
+            );
+            swap_chain = new_swap_chain;
+            views = new_views;
+            resized = false;
+        }
+
+        let eye = camera_controller.update(&mut camera);
+        data.view = camera.build_view_projection(eye, dim);
+        data.eye_position = eye.into();
+        elapsed_seconds += 1.0 / 60.0;
 
         // Get next frame
         let frame = swap_chain.acquire_frame(FrameSync::Semaphore(&frame_semaphore));
@@ -274,13 +690,24 @@ pub fn main() {
         {
             let mut encoder = graphics_pool.acquire_graphics_encoder();
 
-            let locals = Locals { transform: data.transform };
+            if let Some(ref mut prepass) = prepass {
+                prepass.dispatch(&mut encoder, elapsed_seconds);
+            }
+
+            let locals = Locals { model: Matrix4::identity().into() };
             encoder.update_constant_buffer(&data.locals, &locals);
 
+            let light = Light {
+                position: [4.0, -4.0, 6.0, 1.0],
+                color: [1.0, 1.0, 1.0, 1.0],
+            };
+            encoder.update_constant_buffer(&data.light, &light);
+
             encoder.clear(&data.out_color, CLEAR_COLOR);
             encoder.clear_depth(&data.out_depth, 1.0);
 
             encoder.draw(&slice, &pso, &data);
+
             encoder
                 .synced_flush(
                     &mut graphics_queue,
@@ -297,3 +724,160 @@ pub fn main() {
         graphics_pool.reset();
     }
 }
+
+fn run_headless(width: u32, height: u32, output_path: &str) {
+    use self::camera::{Camera, CameraController};
+    use cgmath::{Matrix4, Point3, SquareMatrix};
+
+    let dim = (width, height);
+    let (mut device, adapters) = backend::get_headless_device_and_adapters(dim);
+
+    let gfx::Gpu {
+        mut graphics_queues,
+        ..
+    } = select_adapter(&adapters, parse_gpu_preference()).open_with(
+        |_family, ty| {
+            (ty.supports_graphics() as u32, gfx::QueueType::Graphics)
+        },
+    );
+    let mut graphics_queue = graphics_queues.pop().expect(
+        "Unable to find a graphics queue.",
+    );
+
+    let (out_color, out_depth) = build_offscreen_target(&mut device, dim);
+
+    let pso = create_pso(&mut device);
+    let (cube_verts, cube_indices) = build_cube();
+    let flat_indices: [u16; 6 * 6] = unsafe { std::mem::transmute(cube_indices) };
+    let (vertex_buffer, slice) =
+        device.create_vertex_buffer_with_slice(&cube_verts, flat_indices.as_ref());
+    let mut graphics_pool = graphics_queue.create_graphics_pool(1);
+    let frame_fence = device.create_fence(false);
+
+    let mut camera = Camera::new(Point3::new(0f32, 0., 0.));
+    let camera_controller = CameraController::new(6.0);
+    let eye = camera_controller.update(&mut camera);
+
+    let color_buf = create_color_vertex_buffer(&mut device);
+
+    let data = pipe::Data {
+        vbuf: vertex_buffer,
+        color_buf,
+        view: camera.build_view_projection(eye, dim),
+        eye_position: eye.into(),
+        locals: device.create_constant_buffer(1),
+        light: device.create_constant_buffer(1),
+        out_color: out_color.clone(),
+        out_depth,
+    };
+
+    {
+        let mut encoder = graphics_pool.acquire_graphics_encoder();
+
+        let locals = Locals { model: Matrix4::identity().into() };
+        encoder.update_constant_buffer(&data.locals, &locals);
+
+        let light = Light {
+            position: [4.0, -4.0, 6.0, 1.0],
+            color: [1.0, 1.0, 1.0, 1.0],
+        };
+        encoder.update_constant_buffer(&data.light, &light);
+
+        encoder.clear(&data.out_color, CLEAR_COLOR);
+        encoder.clear_depth(&data.out_depth, 1.0);
+        encoder.draw(&slice, &pso, &data);
+        encoder
+            .synced_flush(&mut graphics_queue, &[], &[], Some(&frame_fence))
+            .expect("Could not flush encoder");
+    }
+
+    device.wait_for_fences(&[&frame_fence], gfx::WaitFor::All, 1_000_000_000);
+
+    let pixels = download_color_texture(&mut device, &mut graphics_queue, &out_color, dim);
+    image::save_buffer(output_path, &pixels, width, height, image::ColorType::RGBA(8))
+        .expect("Can't write PNG");
+}
+
+fn build_offscreen_target(device: &mut backend::Device, dim: (u32, u32)) -> ViewPair {
+    use gfx::texture::{AaMode, DepthStencilDesc, DepthStencilFlags, Info, Kind, RenderDesc};
+    use gfx::handle::{DepthStencilView, RenderTargetView};
+    use gfx::memory::{Bind, Typed, Usage};
+    use gfx::format::Formatted;
+
+    let kind = Kind::D2(dim.0.max(1) as u16, dim.1.max(1) as u16, AaMode::Single);
+
+    let color_tex = device
+        .create_texture_raw(
+            Info {
+                kind,
+                levels: 1,
+                format: ColorFormat::get_format().0,
+                bind: Bind::RENDER_TARGET | Bind::TRANSFER_SRC,
+                usage: Usage::Data,
+            },
+            Some(ColorFormat::get_format().1),
+            None,
+        )
+        .expect("Can't create offscreen color texture");
+    let color_desc = RenderDesc {
+        channel: ColorFormat::get_format().1,
+        level: 0,
+        layer: None,
+    };
+    let rtv = device
+        .view_texture_as_render_target_raw(&color_tex, color_desc)
+        .expect("Can't view offscreen color texture as render target");
+
+    let depth_tex = device
+        .create_texture_raw(
+            Info {
+                kind,
+                levels: 1,
+                format: DepthFormat::get_format().0,
+                bind: Bind::DEPTH_STENCIL,
+                usage: Usage::Data,
+            },
+            None,
+            Some(DepthFormat::get_format().1),
+        )
+        .expect("Can't create offscreen depth texture");
+    let ds_desc = DepthStencilDesc {
+        level: 0,
+        layer: None,
+        flags: DepthStencilFlags::empty(),
+    };
+    let dsv = device
+        .view_texture_as_depth_stencil_raw(&depth_tex, ds_desc)
+        .expect("Can't view offscreen depth texture as depth stencil");
+
+    (Typed::new(rtv), Typed::new(dsv))
+}
+
+fn download_color_texture(
+    device: &mut backend::Device,
+    graphics_queue: &mut backend::GraphicsQueue,
+    color: &gfx::handle::RenderTargetView<backend::Resources, ColorFormat>,
+    dim: (u32, u32),
+) -> Vec<u8> {
+    use gfx::memory::Typed;
+
+    let download = device
+        .create_download_buffer::<[u8; 4]>((dim.0 * dim.1) as usize)
+        .expect("Can't create download buffer");
+
+    let mut graphics_pool = graphics_queue.create_graphics_pool(1);
+    {
+        let mut encoder = graphics_pool.acquire_graphics_encoder();
+        encoder
+            .copy_texture_to_buffer_raw(color.raw().as_texture().unwrap(), None, download.raw())
+            .expect("Can't copy render target to download buffer");
+        encoder
+            .synced_flush(graphics_queue, &[], &[], None)
+            .expect("Could not flush encoder");
+    }
+
+    let reader = device.read_mapping(&download).expect(
+        "Can't map download buffer",
+    );
+    reader.iter().flat_map(|pixel| pixel.iter().cloned()).collect()
+}